@@ -2,15 +2,23 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::fs::File;
-use std::io::Read;
-use std::panic::catch_unwind;
+use std::env;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::sync::{Once, ONCE_INIT};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, Once, ONCE_INIT};
 
 use libmount;
-use nix::mount::{umount2, MntFlags};
+use nix::errno::Errno;
+use nix::mount::{mount, umount, umount2, MntFlags, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{close, fork, getgid, getuid, pipe, ForkResult};
 use uuid::Uuid;
 
 use crate::core::{DevId, DmNameBuf, DmOptions, DmUuidBuf, DM};
@@ -63,19 +71,311 @@ fn execute_cmd(cmd: &mut Command) -> DmResult<()> {
     }
 }
 
+/// Execute a command, returning its captured stdout on success.
+fn execute_cmd_with_output(cmd: &mut Command) -> DmResult<String> {
+    match cmd.output() {
+        Err(err) => Err(DmError::Dm(
+            ErrorEnum::Error,
+            format!("cmd: {:?}, error '{}'", cmd, err.to_string()),
+        )),
+        Ok(result) => {
+            if result.status.success() {
+                Ok(String::from_utf8_lossy(&result.stdout).into_owned())
+            } else {
+                let std_out_txt = String::from_utf8_lossy(&result.stdout);
+                let std_err_txt = String::from_utf8_lossy(&result.stderr);
+                let err_msg = format!(
+                    "cmd: {:?} stdout: {} stderr: {}",
+                    cmd, std_out_txt, std_err_txt
+                );
+                Err(DmError::Dm(ErrorEnum::Error, err_msg))
+            }
+        }
+    }
+}
+
+/// Execute a command, feeding `input` to its stdin. Used for tools that prompt
+/// for confirmation on a tty and read the answer from stdin.
+fn execute_cmd_with_stdin(cmd: &mut Command, input: &str) -> DmResult<()> {
+    let spawn_err = |err: &dyn std::fmt::Display| {
+        DmError::Dm(
+            ErrorEnum::Error,
+            format!("cmd: {:?}, error '{}'", cmd, err),
+        )
+    };
+
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| spawn_err(&e))?;
+    child
+        .stdin
+        .take()
+        .expect("stdin was piped")
+        .write_all(input.as_bytes())
+        .map_err(|e| spawn_err(&e))?;
+
+    let result = child.wait_with_output().map_err(|e| spawn_err(&e))?;
+    if result.status.success() {
+        Ok(())
+    } else {
+        let std_out_txt = String::from_utf8_lossy(&result.stdout);
+        let std_err_txt = String::from_utf8_lossy(&result.stderr);
+        Err(DmError::Dm(
+            ErrorEnum::Error,
+            format!(
+                "cmd: {:?} stdout: {} stderr: {}",
+                cmd, std_out_txt, std_err_txt
+            ),
+        ))
+    }
+}
+
+/// Find the line containing `label` and parse the first UUID-shaped token that
+/// follows it. Matching a specific label rather than the first UUID anywhere in
+/// the output avoids mistaking unrelated UUID-formatted fields (e.g. ext4's
+/// `Directory Hash Seed:`) for the filesystem UUID.
+fn parse_labelled_uuid(text: &str, label: &str) -> DmResult<Uuid> {
+    text.lines()
+        .filter_map(|line| line.find(label).map(|idx| &line[idx + label.len()..]))
+        .filter_map(|rest| {
+            rest.split_whitespace()
+                .filter_map(|tok| Uuid::parse_str(tok).ok())
+                .next()
+        })
+        .next()
+        .ok_or_else(|| {
+            DmError::Dm(
+                ErrorEnum::Error,
+                format!("no UUID found after {:?} in: {}", label, text),
+            )
+        })
+}
+
+/// A filesystem type that the test suite knows how to create and manage.
+#[derive(Clone, Copy, Debug)]
+pub enum TestFs {
+    Xfs,
+    Ext4,
+    Btrfs,
+}
+
+impl TestFs {
+    /// The filesystem type name as understood by `mount(2)`.
+    fn fs_type(self) -> &'static str {
+        match self {
+            TestFs::Xfs => "xfs",
+            TestFs::Ext4 => "ext4",
+            TestFs::Btrfs => "btrfs",
+        }
+    }
+
+    /// Create a filesystem of this type on `devnode`.
+    pub fn create(self, devnode: &Path) -> DmResult<()> {
+        match self {
+            // XFS on the version in Travis does not support specifying a UUID
+            // at mkfs time, so it is set separately via `set_uuid`.
+            TestFs::Xfs => execute_cmd(Command::new("mkfs.xfs").arg("-f").arg("-q").arg(devnode)),
+            TestFs::Ext4 => execute_cmd(Command::new("mkfs.ext4").arg("-F").arg(devnode)),
+            TestFs::Btrfs => execute_cmd(Command::new("mkfs.btrfs").arg("-f").arg(devnode)),
+        }
+    }
+
+    /// Set the UUID of the filesystem on `devnode`.
+    pub fn set_uuid(self, devnode: &Path, uuid: &Uuid) -> DmResult<()> {
+        let uuid_str = format!("{}", uuid);
+        match self {
+            TestFs::Xfs => execute_cmd(
+                Command::new("xfs_admin")
+                    .arg("-U")
+                    .arg(&uuid_str)
+                    .arg(devnode),
+            ),
+            TestFs::Ext4 => execute_cmd(
+                Command::new("tune2fs")
+                    .arg("-U")
+                    .arg(&uuid_str)
+                    .arg(devnode),
+            ),
+            // `btrfstune -U` warns and blocks on an interactive y/N prompt;
+            // pass `-f` and feed confirmation so it runs without a tty.
+            TestFs::Btrfs => execute_cmd_with_stdin(
+                Command::new("btrfstune")
+                    .arg("-f")
+                    .arg("-U")
+                    .arg(&uuid_str)
+                    .arg(devnode),
+                "y\n",
+            ),
+        }
+    }
+
+    /// Read the UUID of the filesystem on `devnode`.
+    pub fn get_uuid(self, devnode: &Path) -> DmResult<Uuid> {
+        let (output, label) = match self {
+            TestFs::Xfs => (
+                execute_cmd_with_output(Command::new("xfs_admin").arg("-u").arg(devnode))?,
+                "UUID =",
+            ),
+            TestFs::Ext4 => (
+                execute_cmd_with_output(Command::new("tune2fs").arg("-l").arg(devnode))?,
+                "Filesystem UUID:",
+            ),
+            TestFs::Btrfs => (
+                execute_cmd_with_output(
+                    Command::new("btrfs")
+                        .arg("filesystem")
+                        .arg("show")
+                        .arg(devnode),
+                )?,
+                "uuid:",
+            ),
+        };
+        parse_labelled_uuid(&output, label)
+    }
+}
+
 /// Generate an XFS FS, does not specify UUID as that's not supported on version in Travis
 pub fn xfs_create_fs(devnode: &Path) -> DmResult<()> {
-    execute_cmd(Command::new("mkfs.xfs").arg("-f").arg("-q").arg(&devnode))
+    TestFs::Xfs.create(devnode)
 }
 
 /// Set a UUID for a XFS volume.
 pub fn xfs_set_uuid(devnode: &Path, uuid: &Uuid) -> DmResult<()> {
-    execute_cmd(
-        Command::new("xfs_admin")
-            .arg("-U")
-            .arg(format!("{}", uuid))
-            .arg(devnode),
+    TestFs::Xfs.set_uuid(devnode, uuid)
+}
+
+fn namespace_err(context: &str, err: &dyn std::fmt::Display) -> DmError {
+    DmError::Dm(ErrorEnum::Error, format!("{}: {}", context, err))
+}
+
+/// Enter a fresh private mount + user namespace in the current process, then
+/// run `f`. `unshare(CLONE_NEWUSER)` requires a single-threaded process, so
+/// this is only ever called from the freshly `fork`'d child in
+/// `with_test_namespace`, which has exactly one thread.
+fn enter_test_namespace<F>(f: F) -> DmResult<()>
+where
+    F: FnOnce(),
+{
+    let uid = getuid();
+    let gid = getgid();
+
+    unshare(CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWUSER)
+        .map_err(|e| namespace_err("failed to unshare mount and user namespace", &e))?;
+
+    // setgroups must be denied before gid_map may be written from an
+    // unprivileged process.
+    fs::write("/proc/self/setgroups", "deny")
+        .map_err(|e| namespace_err("failed to deny setgroups", &e))?;
+    fs::write("/proc/self/uid_map", format!("0 {} 1", uid))
+        .map_err(|e| namespace_err("failed to write uid_map", &e))?;
+    fs::write("/proc/self/gid_map", format!("0 {} 1", gid))
+        .map_err(|e| namespace_err("failed to write gid_map", &e))?;
+
+    mount::<Path, Path, Path, Path>(
+        None,
+        Path::new("/"),
+        None,
+        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+        None,
     )
+    .map_err(|e| namespace_err("failed to remount / as private", &e))?;
+
+    f();
+    Ok(())
+}
+
+/// Run `f` inside a fresh private mount + user namespace so that filesystems
+/// mounted by the closure are invisible to the host and disappear when the
+/// namespace exits. This makes `clean_up` a belt-and-suspenders step for
+/// mount state rather than its sole guarantee, and lets unprivileged CI run
+/// the mount-dependent tests.
+///
+/// `unshare(CLONE_NEWUSER)` fails with `EINVAL` in a multithreaded process, and
+/// a `cargo test` binary is always multithreaded (harness plus per-test worker
+/// threads, even under `--test-threads=1`). `f` is therefore run in a freshly
+/// `fork`'d child — which has a single thread — that does the unshare, maps the
+/// invoking uid/gid to root, remounts `/` as `MS_REC | MS_PRIVATE`, and runs
+/// `f`. The parent waits for the child and reports a non-zero exit as an error.
+///
+/// Because `f` runs in the child, process-global state it registers is recorded
+/// only in the child's memory and is invisible to the parent's `clean_up`.
+/// Registry-based fixtures in particular — a `LoopDevice`, whose detach/backing
+/// -file removal relies on the in-process `LOOP_DEVICES` registry — MUST be
+/// allocated outside the namespace (in the parent, before calling this helper);
+/// one created inside the closure would leak with no cleanup. Filesystems
+/// mounted inside the closure are exempt, since they vanish when the namespace
+/// exits. Only mount/unmount operations are namespaced: `get_dm()` still talks
+/// to the host device-mapper control node, so any DM devices created inside the
+/// closure are real and must still be removed by `clean_up` (from the parent).
+///
+/// On failure the child's reason is forwarded to the parent over a pipe so the
+/// real error — including a panic/assertion message from `f` — survives rather
+/// than being collapsed to an opaque exit code.
+pub fn with_test_namespace<F>(f: F) -> DmResult<()>
+where
+    F: FnOnce(),
+{
+    let (read_fd, write_fd) =
+        pipe().map_err(|e| namespace_err("failed to create status pipe", &e))?;
+
+    match fork().map_err(|e| namespace_err("failed to fork test namespace child", &e))? {
+        ForkResult::Parent { child } => {
+            let _ = close(write_fd);
+            // Safety: the child holds the only other reference to write_fd and
+            // we take sole ownership of read_fd here.
+            let mut reason = String::new();
+            let _ = unsafe { File::from_raw_fd(read_fd) }.read_to_string(&mut reason);
+
+            match waitpid(child, None)
+                .map_err(|e| namespace_err("failed to wait for test namespace child", &e))?
+            {
+                WaitStatus::Exited(_, 0) => Ok(()),
+                other => {
+                    let detail = if reason.is_empty() {
+                        format!("{:?}", other)
+                    } else {
+                        reason
+                    };
+                    Err(namespace_err("test namespace child failed", &detail))
+                }
+            }
+        }
+        ForkResult::Child => {
+            let _ = close(read_fd);
+            // The child must never return into the test harness; always exit.
+            let code = match catch_unwind(AssertUnwindSafe(|| enter_test_namespace(f))) {
+                Ok(Ok(())) => 0,
+                Ok(Err(e)) => {
+                    report_child_failure(write_fd, &format!("{}", e));
+                    1
+                }
+                Err(payload) => {
+                    let msg = payload
+                        .downcast_ref::<&str>()
+                        .map(|s| s.to_string())
+                        .or_else(|| payload.downcast_ref::<String>().cloned())
+                        .unwrap_or_else(|| "panic in test namespace closure".to_string());
+                    report_child_failure(write_fd, &msg);
+                    1
+                }
+            };
+            let _ = close(write_fd);
+            std::process::exit(code);
+        }
+    }
+}
+
+/// Best-effort write of the child's failure reason to the status pipe.
+fn report_child_failure(write_fd: std::os::unix::io::RawFd, msg: &str) {
+    // Safety: the parent has closed its copy of write_fd; the child owns it.
+    let mut pipe = unsafe { File::from_raw_fd(write_fd) };
+    let _ = pipe.write_all(msg.as_bytes());
+    // Leak the File so its Drop does not close write_fd; the explicit close in
+    // the caller owns that.
+    std::mem::forget(pipe);
 }
 
 /// Wait for udev activity to be done.
@@ -83,6 +383,173 @@ pub fn udev_settle() -> DmResult<()> {
     execute_cmd(Command::new("udevadm").arg("settle"))
 }
 
+// Legacy loop-device ioctls, which use bare request codes rather than the
+// _IOR/_IOW size-encoded form.
+ioctl_none_bad!(loop_ctl_get_free, 0x4C82);
+ioctl_write_int_bad!(loop_set_fd, 0x4C00);
+ioctl_none_bad!(loop_clr_fd, 0x4C01);
+
+/// Registry of loop devices allocated by `LoopDevice` so that `clean_up` can
+/// detach them and delete their backing files.
+static LOOP_INIT: Once = ONCE_INIT;
+static mut LOOP_DEVICES: Option<Mutex<Vec<LoopBacking>>> = None;
+
+/// Monotonic counter used to give each loop backing file a unique name.
+static LOOP_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/// A loop device and the backing file attached to it.
+struct LoopBacking {
+    loop_path: PathBuf,
+    backing_file: PathBuf,
+}
+
+fn loop_devices() -> &'static Mutex<Vec<LoopBacking>> {
+    unsafe {
+        LOOP_INIT.call_once(|| LOOP_DEVICES = Some(Mutex::new(Vec::new())));
+        match LOOP_DEVICES {
+            Some(ref registry) => registry,
+            _ => panic!("LOOP_DEVICES.is_some()"),
+        }
+    }
+}
+
+fn loop_err(context: &str, err: &dyn std::fmt::Display) -> DmError {
+    DmError::Dm(ErrorEnum::Error, format!("{}: {}", context, err))
+}
+
+/// A self-contained backing block device for DM tests. Creates a sparse backing
+/// file under the temp dir (named with `DM_TEST_ID` so the cleanup sweep also
+/// catches leftovers) and attaches it to a free loop device. The device is
+/// tracked so `clean_up` detaches it and removes the backing file.
+pub struct LoopDevice {
+    loop_path: PathBuf,
+    backing_file: PathBuf,
+}
+
+impl LoopDevice {
+    /// Create a `size`-byte sparse backing file and attach it to a free loop
+    /// device, returning the fixture.
+    pub fn new(size: u64) -> DmResult<LoopDevice> {
+        let seq = LOOP_SEQ.fetch_add(1, Ordering::SeqCst);
+        let backing_file = env::temp_dir().join(test_string(&format!("loop_backing_{}", seq)));
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&backing_file)
+            .map_err(|e| loop_err("failed to create loop backing file", &e))?;
+        file.set_len(size)
+            .map_err(|e| loop_err("failed to size loop backing file", &e))?;
+
+        let control = File::open("/dev/loop-control")
+            .map_err(|e| loop_err("failed to open /dev/loop-control", &e))?;
+        let index = unsafe { loop_ctl_get_free(control.as_raw_fd()) }
+            .map_err(|e| loop_err("LOOP_CTL_GET_FREE failed", &e))?;
+        let loop_path = PathBuf::from(format!("/dev/loop{}", index));
+
+        let loop_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&loop_path)
+            .map_err(|e| loop_err("failed to open loop device", &e))?;
+        unsafe { loop_set_fd(loop_file.as_raw_fd(), file.as_raw_fd()) }
+            .map_err(|e| loop_err("LOOP_SET_FD failed", &e))?;
+
+        loop_devices()
+            .lock()
+            .expect("loop device registry poisoned")
+            .push(LoopBacking {
+                loop_path: loop_path.clone(),
+                backing_file: backing_file.clone(),
+            });
+
+        Ok(LoopDevice {
+            loop_path,
+            backing_file,
+        })
+    }
+
+    /// The `/dev/loopN` path to use as a DM target backing device.
+    pub fn devnode(&self) -> &Path {
+        &self.loop_path
+    }
+
+    /// The path of the sparse file backing this loop device.
+    pub fn backing_file(&self) -> &Path {
+        &self.backing_file
+    }
+}
+
+/// Mount propagation mode to apply to a test mount point, mirroring how
+/// container runtimes configure rootfs propagation.
+#[derive(Clone, Copy, Debug)]
+pub enum MountPropagation {
+    Private,
+    Slave,
+    Shared,
+}
+
+impl MountPropagation {
+    fn flag(self) -> MsFlags {
+        match self {
+            MountPropagation::Private => MsFlags::MS_PRIVATE,
+            MountPropagation::Slave => MsFlags::MS_SLAVE,
+            MountPropagation::Shared => MsFlags::MS_SHARED,
+        }
+    }
+}
+
+/// Mount the `fs`-type filesystem on `devnode` at `mount_point`, then set its
+/// propagation mode. The mount point is created if missing and should be named
+/// with `DM_TEST_ID` so the cleanup sweep catches it. Propagation is applied
+/// with a second recursive `mount` call so tests can validate DM behaviour
+/// under private vs. slave vs. shared propagation.
+pub fn mount_fs(
+    devnode: &Path,
+    mount_point: &Path,
+    fs: TestFs,
+    propagation: MountPropagation,
+) -> DmResult<()> {
+    fs::create_dir_all(mount_point).map_err(|e| {
+        DmError::Dm(
+            ErrorEnum::Error,
+            format!("failed to create mount point {:?}: {}", mount_point, e),
+        )
+    })?;
+
+    mount::<Path, Path, str, Path>(
+        Some(devnode),
+        mount_point,
+        Some(fs.fs_type()),
+        MsFlags::empty(),
+        None,
+    )
+    .map_err(|e| {
+        DmError::Dm(
+            ErrorEnum::Error,
+            format!("failed to mount {:?} at {:?}: {}", devnode, mount_point, e),
+        )
+    })?;
+
+    mount::<Path, Path, Path, Path>(
+        None,
+        mount_point,
+        None,
+        MsFlags::MS_REC | propagation.flag(),
+        None,
+    )
+    .map_err(|e| {
+        DmError::Dm(
+            ErrorEnum::Error,
+            format!("failed to set propagation on {:?}: {}", mount_point, e),
+        )
+    })?;
+
+    Ok(())
+}
+
 /// Generate the test name given the test supplied name.
 pub fn test_name(name: &str) -> DmResult<DmNameBuf> {
     DmNameBuf::new(test_string(name))
@@ -166,30 +633,132 @@ fn dm_test_devices_remove() -> Result<()> {
     .map_err(|e| e.chain_err(|| "Failed to ensure removal of all test-generated DM devices"))
 }
 
+/// Number of plain unmount attempts (the first try included) made before
+/// resorting to a lazy detach.
+const UNMOUNT_RETRIES: usize = 3;
+
+/// Unmount a single mount point, retrying a bounded number of times. A plain
+/// `umount` is attempted up to `UNMOUNT_RETRIES` times, repeating only while it
+/// reports `EBUSY`; if it still fails, or fails with any other error, a lazy
+/// `MNT_DETACH` unmount is made as a fallback. `EINVAL` (not a mount point)
+/// counts as already unmounted. Return `true` if the path ended up unmounted.
+fn unmount_with_retries(mount_point: &Path) -> bool {
+    for attempt in 0..UNMOUNT_RETRIES {
+        match umount(mount_point) {
+            Ok(()) => return true,
+            Err(nix::Error::Sys(Errno::EINVAL)) => return true,
+            Err(nix::Error::Sys(Errno::EBUSY)) if attempt + 1 < UNMOUNT_RETRIES => continue,
+            Err(_) => break,
+        }
+    }
+    umount2(mount_point, MntFlags::MNT_DETACH).is_ok()
+}
+
 /// Unmount any filesystems that contain DM_TEST_ID in the mount point.
-/// Return immediately on the first unmount failure.
+/// Descendant mount points (e.g. a bind or overlay stacked on a DM-backed FS)
+/// must come off before their parents, so matching mount points are unmounted
+/// deepest-first by path component count. Rather than aborting on the first
+/// failure, still-mounted paths are accumulated and returned so `clean_up`
+/// reports exactly what leaked, mirroring `dm_test_devices_remove`.
 fn dm_test_fs_unmount() -> Result<()> {
     || -> Result<()> {
         let mut mount_data = String::new();
         File::open("/proc/self/mountinfo")?.read_to_string(&mut mount_data)?;
         let parser = libmount::mountinfo::Parser::new(mount_data.as_bytes());
 
-        for mount_point in parser
+        let mut mount_points = parser
             .filter_map(|x| x.ok())
             .filter_map(|m| m.mount_point.into_owned().into_string().ok())
             .filter(|mp| mp.contains(DM_TEST_ID))
-        {
-            umount2(&PathBuf::from(mount_point), MntFlags::MNT_DETACH)?;
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+
+        // Deepest paths first so a child is always unmounted before its parent.
+        mount_points.sort_by_key(|mp| mp.components().count());
+        mount_points.reverse();
+
+        let remain = mount_points
+            .into_iter()
+            .filter(|mp| !unmount_with_retries(mp))
+            .map(|mp| mp.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+
+        if remain.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Some test-generated filesystems remaining: {:?}", remain).into())
         }
-        Ok(())
     }()
     .map_err(|e| e.chain_err(|| "Failed to ensure all test-generated filesystems were unmounted"))
 }
 
+/// Detach all loop devices allocated by `LoopDevice` and remove their backing
+/// files. Accumulate the paths that could not be detached and return them in
+/// the error, mirroring `dm_test_devices_remove`.
+fn loop_devices_detach() -> Result<()> {
+    let mut remain = Vec::new();
+    let mut devices = loop_devices().lock().expect("loop device registry poisoned");
+
+    for backing in devices.drain(..) {
+        let detached = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&backing.loop_path)
+            .map_err(Error::from)
+            .and_then(|f| unsafe { loop_clr_fd(f.as_raw_fd()) }.map(|_| ()).map_err(Error::from));
+
+        let _ = fs::remove_file(&backing.backing_file);
+
+        if detached.is_err() {
+            remain.push(backing.loop_path.to_string_lossy().into_owned());
+        }
+    }
+
+    if remain.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Some test-generated loop devices remaining: {:?}", remain).into())
+    }
+}
+
+/// Remove any leftover files in the temp dir whose names contain DM_TEST_ID,
+/// e.g. `LoopDevice` backing files orphaned when the in-process registry was
+/// lost (a crash/kill, or a `with_test_namespace` child that exits without
+/// handing state back to the parent). Accumulate the paths that could not be
+/// removed and return them in the error, mirroring `dm_test_devices_remove`.
+fn dm_test_temp_files_remove() -> Result<()> {
+    || -> Result<()> {
+        let mut remain = Vec::new();
+
+        for entry in fs::read_dir(env::temp_dir())? {
+            let path = entry?.path();
+            let matches = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .map_or(false, |n| n.contains(DM_TEST_ID));
+            if matches && fs::remove_file(&path).is_err() {
+                remain.push(path.to_string_lossy().into_owned());
+            }
+        }
+
+        if remain.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Some test-generated temp files remaining: {:?}", remain).into())
+        }
+    }()
+    .map_err(|e| e.chain_err(|| "Failed to ensure all test-generated temp files were removed"))
+}
+
 /// Unmount any filesystems or devicemapper devices which contain DM_TEST_ID
-/// in the path or name. Immediately return on first error.
+/// in the path or name, detach any loop devices allocated by the suite, then
+/// remove leftover DM_TEST_ID-named temp files. Each stage accumulates and
+/// reports what it could not clean up; the first stage that fails ends the
+/// sweep, and the stages after it are not run.
 pub(super) fn clean_up() -> Result<()> {
     dm_test_fs_unmount()?;
     dm_test_devices_remove()?;
+    loop_devices_detach()?;
+    dm_test_temp_files_remove()?;
     Ok(())
 }